@@ -1,14 +1,87 @@
-use core::{ops::RangeInclusive, ptr::NonNull};
+use core::{
+    ops::{Range, RangeInclusive},
+    ptr::NonNull,
+};
 
 use x86_64::{
-    registers::control::{Cr3, Cr3Flags},
-    structures::paging::{PageTable, PageTableIndex},
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    registers::{
+        control::{Cr3, Cr3Flags},
+        model_specific::{Pat, PatMemoryType},
+    },
+    structures::paging::{PageTable, PageTableEntry, PageTableFlags, PageTableIndex},
 };
 
 use crate::*;
 
 use super::page_table_with_level::{PageTableLevel, PageTableWithLevelMut};
 
+/// The PAT selector occupies bit 7 of a 4 KiB leaf entry, but that bit is
+/// already named [`PageTableFlags::HUGE_PAGE`] in this crate's dependency,
+/// so it can be tested directly. Huge entries instead carry it at bit 12,
+/// which sits inside the address field's bit range and so survives a plain
+/// `PageTableEntry::addr()` call.
+const HUGE_PAT_BIT: u64 = 1 << 12;
+
+const PAGE_SIZE: u64 = 0x1000;
+const MIB_2: u64 = 0x20_0000;
+const GIB_1: u64 = 0x4000_0000;
+
+/// Inverse of [`ManagedPat::get_page_table_flags`]: recover the PAT memory
+/// type that produced the given cache-control flags (and PAT-selector bit)
+/// in a page-table entry. `Pat::read()` yields all eight PAT entries and the
+/// index built here is at most 7, so this always returns `Some`.
+fn decode_pat_memory_type(flags: PageTableFlags, pat_bit: bool) -> Option<PatMemoryType> {
+    Pat::read().get(pat_msr_index(flags, pat_bit)).copied()
+}
+
+/// See Intel SDM -> Volume 3 -> 13.12.3 Selecting a Memory Type from the PAT.
+fn pat_msr_index(flags: PageTableFlags, pat_bit: bool) -> usize {
+    let mut pat_msr_index = 0usize;
+    if flags.contains(PageTableFlags::WRITE_THROUGH) {
+        pat_msr_index |= 0b001;
+    }
+    if flags.contains(PageTableFlags::NO_CACHE) {
+        pat_msr_index |= 0b010;
+    }
+    if pat_bit {
+        pat_msr_index |= 0b100;
+    }
+    pat_msr_index
+}
+
+/// Whether a `block_size`-sized block starting at `virt` is entirely inside
+/// the next `remaining` bytes of a request, i.e. clearing/reflagging it in
+/// place fully satisfies the request instead of only partially covering it.
+fn fully_covers(virt: VirtAddr, block_size: u64, remaining: u64) -> bool {
+    virt.as_u64() % block_size == 0 && remaining >= block_size
+}
+
+/// How far a cursor walking in `block_size` steps can jump forward from
+/// `virt` when nothing is mapped there: the rest of the `block_size`-aligned
+/// span it currently sits in, capped by `remaining` so it never overshoots
+/// the end of the request.
+fn skip_span(virt: VirtAddr, block_size: u64, remaining: u64) -> u64 {
+    (block_size - virt.as_u64() % block_size).min(remaining)
+}
+
+/// Splitting a 2 MiB block into 4 KiB leaves moves its PAT selector from bit
+/// 12, where a huge entry's address field hides it, to bit 7 (this crate's
+/// [`HUGE_PAT_BIT`] comment explains why that's [`PageTableFlags::HUGE_PAGE`]
+/// for a 4 KiB leaf). `huge_addr` has to have that bit masked out too, since
+/// otherwise it leaks into the per-child `+ i * sub_page_size` arithmetic and
+/// offsets every split leaf's physical base by 4 KiB.
+fn relocate_huge_pat_bit(huge_addr: PhysAddr, huge_flags: PageTableFlags) -> (PhysAddr, PageTableFlags) {
+    let pat_bit = huge_addr.as_u64() & HUGE_PAT_BIT != 0;
+    let base = PhysAddr::new(huge_addr.as_u64() & !HUGE_PAT_BIT);
+    let mut flags = huge_flags & !PageTableFlags::HUGE_PAGE;
+    if pat_bit {
+        flags |= PageTableFlags::HUGE_PAGE;
+    }
+    (base, flags)
+}
+
 #[derive(Debug)]
 pub struct KernelL4Data {
     is_referenced: bool,
@@ -133,4 +206,652 @@ impl ManagedL4PageTable {
     pub fn frame(&self) -> &Owned4KibFrame {
         &self.frame
     }
+
+    /// Whether this table is the one currently loaded into `CR3`.
+    ///
+    /// `map_range`/`unmap_range` use this to decide whether a modified entry
+    /// needs a TLB flush: an inactive table's entries can never be cached,
+    /// since the CPU only walks the table that CR3 points at. This lets
+    /// callers build and edit a whole new address space with
+    /// [`Self::map_range`] before ever switching to it, with none of the
+    /// flush overhead that editing a live table requires.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0.start_address() == self.frame.start_address()
+    }
+
+    /// Flush `addr` from the TLB, but only if this table is active; see
+    /// [`Self::is_active`].
+    fn maybe_flush(&self, addr: VirtAddr) {
+        if self.is_active() {
+            tlb::flush(addr);
+        }
+    }
+
+    /// Walk the four page-table levels to resolve `addr` to its backing
+    /// physical address and effective PAT memory type.
+    ///
+    /// Returns `None` if `addr` is not mapped, i.e. some level along the walk
+    /// has `PRESENT` clear.
+    ///
+    /// Works under both [`PagingAccessMode::Offset`] and
+    /// [`PagingAccessMode::Recursive`] configs: unlike [`Self::page_table`],
+    /// which resolves the L4 frame through the fixed-offset scheme, this
+    /// resolves it through [`PagingConfig::child_table_virt`] (with
+    /// `levels_from_l4 == 0`) so the whole walk goes through the same
+    /// recursion-aware path.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PatMemoryType)> {
+        let l4_virt = self.config.child_table_virt(addr, 0, self.frame.start_address());
+        let l4_table = unsafe { &*l4_virt.as_ptr::<PageTable>() };
+        let l4_entry = &l4_table[addr.p4_index()];
+        if !l4_entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        let l3_table = unsafe { &*self.config.child_table_virt(addr, 1, l4_entry.addr()).as_ptr::<PageTable>() };
+        let l3_entry = &l3_table[addr.p3_index()];
+        if !l3_entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let frame_base = l3_entry.addr().as_u64() & !((1 << 30) - 1);
+            let pat_bit = l3_entry.addr().as_u64() & HUGE_PAT_BIT != 0;
+            let phys = PhysAddr::new(frame_base + (addr.as_u64() & ((1 << 30) - 1)));
+            let memory_type = decode_pat_memory_type(l3_entry.flags(), pat_bit)?;
+            return Some((phys, memory_type));
+        }
+
+        let l2_table = unsafe { &*self.config.child_table_virt(addr, 2, l3_entry.addr()).as_ptr::<PageTable>() };
+        let l2_entry = &l2_table[addr.p2_index()];
+        if !l2_entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let frame_base = l2_entry.addr().as_u64() & !((1 << 21) - 1);
+            let pat_bit = l2_entry.addr().as_u64() & HUGE_PAT_BIT != 0;
+            let phys = PhysAddr::new(frame_base + (addr.as_u64() & ((1 << 21) - 1)));
+            let memory_type = decode_pat_memory_type(l2_entry.flags(), pat_bit)?;
+            return Some((phys, memory_type));
+        }
+
+        let l1_table = unsafe { &*self.config.child_table_virt(addr, 3, l2_entry.addr()).as_ptr::<PageTable>() };
+        let l1_entry = &l1_table[addr.p1_index()];
+        if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let pat_bit = l1_entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        let phys = l1_entry.addr() + u64::from(addr.page_offset());
+        let memory_type = decode_pat_memory_type(l1_entry.flags(), pat_bit)?;
+        Some((phys, memory_type))
+    }
+
+    /// Map `virt` to consecutive physical frames starting at `phys_start`,
+    /// allocating and zeroing intermediate tables on demand wherever the walk
+    /// down from L4 finds a non-`PRESENT` entry.
+    ///
+    /// Whenever the remaining span is naturally aligned in both `virt` and
+    /// physical address (2 MiB or 1 GiB on both sides) and at least that
+    /// large, a single `HUGE_PAGE` block entry is installed at L2/L3 instead
+    /// of populating a full child table down to 4 KiB.
+    ///
+    /// # Panics
+    /// Panics if `virt` is not 4 KiB aligned at both ends, if any page in
+    /// `virt` falls outside this table's [`L4Type::l4_managed_entry_range`],
+    /// or if `alloc` cannot supply a frame for a new intermediate table.
+    ///
+    /// If this table [`Self::is_active`], every entry changed from
+    /// non-`PRESENT` can still be stale in the TLB as a cached "not mapped"
+    /// result, so the whole range is flushed with `tlb::flush_all()` once
+    /// mapping completes; an inactive table skips this entirely.
+    pub fn map_range(
+        &mut self,
+        virt: Range<VirtAddr>,
+        phys_start: PhysAddr,
+        flags: PageTableFlags,
+        alloc: &mut impl FrameAllocator,
+    ) {
+        self.config.assert_offset_mode("map_range");
+        assert_eq!(virt.start.as_u64() % PAGE_SIZE, 0, "virt.start must be 4 KiB aligned");
+        assert_eq!(virt.end.as_u64() % PAGE_SIZE, 0, "virt.end must be 4 KiB aligned");
+
+        let mut virt_cursor = virt.start;
+        let mut phys_cursor = phys_start;
+        while virt_cursor < virt.end {
+            let remaining = virt.end.as_u64() - virt_cursor.as_u64();
+            let block_size = Self::coalesced_block_size(virt_cursor, phys_cursor, remaining);
+            self.map_block(virt_cursor, phys_cursor, block_size, flags, alloc);
+            virt_cursor += block_size;
+            phys_cursor += block_size;
+        }
+
+        if self.is_active() {
+            tlb::flush_all();
+        }
+    }
+
+    /// Largest page size (1 GiB, 2 MiB or 4 KiB) that `virt`/`phys` are both
+    /// aligned to and that still fits within `remaining` bytes.
+    fn coalesced_block_size(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> u64 {
+        let aligned_to = |size: u64| virt.as_u64() % size == 0 && phys.as_u64() % size == 0;
+        if remaining >= GIB_1 && aligned_to(GIB_1) {
+            GIB_1
+        } else if remaining >= MIB_2 && aligned_to(MIB_2) {
+            MIB_2
+        } else {
+            PAGE_SIZE
+        }
+    }
+
+    fn map_block(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        block_size: u64,
+        flags: PageTableFlags,
+        alloc: &mut impl FrameAllocator,
+    ) {
+        assert!(
+            self._type.l4_managed_entry_range().contains(&virt.p4_index()),
+            "{virt:?} is outside this table's managed L4 range",
+        );
+
+        let l4_table = self.page_table();
+        let l3_table = self.next_table_mut(l4_table, virt.p4_index(), flags, alloc);
+        if block_size == GIB_1 {
+            let entry = unsafe { &mut l3_table.as_mut()[virt.p3_index()] };
+            assert!(
+                !entry.flags().contains(PageTableFlags::PRESENT),
+                "{virt:?} is already mapped; unmap it before mapping over it",
+            );
+            entry.set_addr(phys, flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+            return;
+        }
+
+        let l2_table = self.next_table_mut(l3_table, virt.p3_index(), flags, alloc);
+        if block_size == MIB_2 {
+            let entry = unsafe { &mut l2_table.as_mut()[virt.p2_index()] };
+            assert!(
+                !entry.flags().contains(PageTableFlags::PRESENT),
+                "{virt:?} is already mapped; unmap it before mapping over it",
+            );
+            entry.set_addr(phys, flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+            return;
+        }
+
+        let l1_table = self.next_table_mut(l2_table, virt.p2_index(), flags, alloc);
+        let entry = unsafe { &mut l1_table.as_mut()[virt.p1_index()] };
+        assert!(
+            !entry.flags().contains(PageTableFlags::PRESENT),
+            "{virt:?} is already mapped; unmap it before mapping over it",
+        );
+        entry.set_addr(phys, flags | PageTableFlags::PRESENT);
+    }
+
+    /// Map `region` so that every virtual address equals its physical
+    /// address, e.g. for firmware/bootstrap identity maps. Goes through
+    /// [`Self::map_range`], so large identity regions are coalesced into
+    /// huge pages rather than thousands of 4 KiB tables.
+    pub fn map_identity(&mut self, region: Range<PhysAddr>, flags: PageTableFlags, alloc: &mut impl FrameAllocator) {
+        let virt = VirtAddr::new(region.start.as_u64())..VirtAddr::new(region.end.as_u64());
+        self.map_range(virt, region.start, flags, alloc);
+    }
+
+    /// Map every physical frame in `region` at `phys + virt_offset`, i.e. the
+    /// "map all physical memory at a fixed offset" scheme
+    /// [`PagingAccessMode::Offset`] already assumes. Goes through
+    /// [`Self::map_range`], so large linear regions are coalesced into huge
+    /// pages rather than thousands of 4 KiB tables.
+    pub fn map_linear(
+        &mut self,
+        region: Range<PhysAddr>,
+        virt_offset: VirtAddr,
+        flags: PageTableFlags,
+        alloc: &mut impl FrameAllocator,
+    ) {
+        let virt = (virt_offset + region.start.as_u64())..(virt_offset + region.end.as_u64());
+        self.map_range(virt, region.start, flags, alloc);
+    }
+
+    /// Ensure the page at `at` is backed by concrete intermediate tables down
+    /// to L1, while leaving the leaf entry itself non-`PRESENT`. A stack
+    /// overflow into this page then faults instead of silently corrupting
+    /// whatever memory happens to sit above it, which is what happens if the
+    /// guard region is just a hole left to be mapped (and so coalesced away)
+    /// later.
+    ///
+    /// `flags` should match the flags of the mapping the guard page sits
+    /// next to (e.g. the stack it guards), so that [`PageTableFlags::USER_ACCESSIBLE`]
+    /// propagates onto the intermediates the guard page creates; see
+    /// [`Self::next_table_mut`].
+    ///
+    /// # Panics
+    /// Panics if `at` falls outside this table's
+    /// [`L4Type::l4_managed_entry_range`], or if `alloc` cannot supply a
+    /// frame for a new intermediate table.
+    pub fn create_guard_page(&mut self, at: VirtAddr, flags: PageTableFlags, alloc: &mut impl FrameAllocator) {
+        self.config.assert_offset_mode("create_guard_page");
+        assert!(
+            self._type.l4_managed_entry_range().contains(&at.p4_index()),
+            "{at:?} is outside this table's managed L4 range",
+        );
+
+        let l4_table = self.page_table();
+        let l3_table = self.next_table_mut(l4_table, at.p4_index(), flags, alloc);
+        let l2_table = self.next_table_mut(l3_table, at.p3_index(), flags, alloc);
+        let l1_table = self.next_table_mut(l2_table, at.p2_index(), flags, alloc);
+
+        let entry = unsafe { &mut l1_table.as_mut()[at.p1_index()] };
+        entry.set_unused();
+    }
+
+    /// Map a `page_count`-page stack ending just below `top`, backed by
+    /// consecutive frames starting at `phys_start`, with a guard page placed
+    /// directly below the stack's lowest page.
+    ///
+    /// # Panics
+    /// Panics for the same reasons as [`Self::map_range`] and
+    /// [`Self::create_guard_page`].
+    pub fn map_stack_with_guard(
+        &mut self,
+        top: VirtAddr,
+        page_count: u64,
+        phys_start: PhysAddr,
+        flags: PageTableFlags,
+        alloc: &mut impl FrameAllocator,
+    ) {
+        let bottom = top - page_count * PAGE_SIZE;
+        self.map_range(bottom..top, phys_start, flags, alloc);
+        self.create_guard_page(bottom - PAGE_SIZE, flags, alloc);
+    }
+
+    /// Return the child table pointed to by `table[index]`, allocating and
+    /// zeroing a fresh one via `alloc` if the entry is not yet `PRESENT`.
+    ///
+    /// `leaf_flags` is the flags of the mapping this intermediate is being
+    /// created for. The CPU ANDs the `USER_ACCESSIBLE` bit across every
+    /// level on the way to a leaf, so a leaf's own `USER_ACCESSIBLE` only
+    /// takes effect if every intermediate above it carries the bit too; it's
+    /// propagated here, widening an already-`PRESENT` intermediate's access
+    /// if needed rather than just setting it on newly allocated ones. That
+    /// widening is always safe: the leaf's own flags still gate access more
+    /// tightly than the intermediate's.
+    ///
+    /// # Panics
+    /// Panics if `table[index]` is already `PRESENT` as a `HUGE_PAGE` block:
+    /// its frame holds mapped data, not a page table, so treating it as one
+    /// would corrupt that data and misinterpret it as page table entries.
+    fn next_table_mut(
+        &self,
+        mut table: NonNull<PageTable>,
+        index: PageTableIndex,
+        leaf_flags: PageTableFlags,
+        alloc: &mut impl FrameAllocator,
+    ) -> NonNull<PageTable> {
+        let user_accessible = leaf_flags & PageTableFlags::USER_ACCESSIBLE;
+        let entry = unsafe { &mut table.as_mut()[index] };
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            let mut new_frame = alloc
+                .allocate_frame()
+                .expect("out of physical memory for intermediate page tables");
+            unsafe { init_page_table(&mut new_frame, &self.config) };
+            entry.set_addr(
+                new_frame.start_address(),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | user_accessible,
+            );
+            // Ownership of the frame now lives in the page table entry, not in a Rust value.
+            core::mem::forget(new_frame);
+        } else {
+            assert!(
+                !entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                "{index:?} is already mapped as a HUGE_PAGE block, not an intermediate table",
+            );
+            if !entry.flags().contains(user_accessible) {
+                let addr = entry.addr();
+                let flags = entry.flags() | user_accessible;
+                entry.set_addr(addr, flags);
+            }
+        }
+        NonNull::new(entry.addr().to_virt(&self.config).as_mut_ptr::<PageTable>()).unwrap()
+    }
+
+    /// Clear every leaf entry in `virt`, handing each freed frame to
+    /// `on_freed`. If `free_empty_intermediates` is set, intermediate tables
+    /// that become entirely empty as a result are freed the same way.
+    ///
+    /// A `HUGE_PAGE` block that `virt` only partially covers is first split
+    /// into a full child table at the next-smaller granularity (see
+    /// [`Self::split_block`]) so the unaffected part of the block stays
+    /// mapped; a block `virt` covers in full is cleared directly instead,
+    /// without ever allocating a child table for it (see
+    /// [`fully_covers`]). If this table [`Self::is_active`], every
+    /// split and every cleared entry is flushed from the TLB as it happens;
+    /// an inactive table skips this entirely.
+    ///
+    /// # Panics
+    /// Panics if `virt` is not 4 KiB aligned at both ends, or if `alloc`
+    /// cannot supply a frame to split a block that `virt` only partially
+    /// covers.
+    pub fn unmap_range(
+        &mut self,
+        virt: Range<VirtAddr>,
+        free_empty_intermediates: bool,
+        alloc: &mut impl FrameAllocator,
+        mut on_freed: impl FnMut(Owned4KibFrame),
+    ) {
+        self.config.assert_offset_mode("unmap_range");
+        assert_eq!(virt.start.as_u64() % PAGE_SIZE, 0, "virt.start must be 4 KiB aligned");
+        assert_eq!(virt.end.as_u64() % PAGE_SIZE, 0, "virt.end must be 4 KiB aligned");
+
+        let mut virt_cursor = virt.start;
+        while virt_cursor < virt.end {
+            let remaining = virt.end.as_u64() - virt_cursor.as_u64();
+            virt_cursor += self.unmap_block(virt_cursor, remaining, free_empty_intermediates, alloc, &mut on_freed);
+        }
+    }
+
+    /// Clear whatever is mapped at `virt`, splitting a `HUGE_PAGE` block
+    /// first only if `remaining` doesn't cover it in full. Returns how many
+    /// bytes starting at `virt` were accounted for, so the caller can
+    /// advance its cursor by that much.
+    fn unmap_block(
+        &mut self,
+        virt: VirtAddr,
+        remaining: u64,
+        free_empty_intermediates: bool,
+        alloc: &mut impl FrameAllocator,
+        on_freed: &mut impl FnMut(Owned4KibFrame),
+    ) -> u64 {
+        let l4_table = unsafe { self.page_table().as_mut() };
+        let l3_table = match Self::child_table_mut(l4_table, virt.p4_index(), &self.config) {
+            Some(table) => table,
+            None => return skip_span(virt, GIB_1, remaining),
+        };
+
+        if l3_table[virt.p3_index()].flags().contains(PageTableFlags::HUGE_PAGE) && fully_covers(virt, GIB_1, remaining) {
+            let entry = &mut l3_table[virt.p3_index()];
+            let freed = PhysAddr::new(entry.addr().as_u64() & !HUGE_PAT_BIT);
+            entry.set_unused();
+            self.maybe_flush(virt);
+            on_freed(unsafe { Owned4KibFrame::from_mapped(freed) });
+            if free_empty_intermediates {
+                Self::free_if_empty(l3_table, &mut l4_table[virt.p4_index()], on_freed);
+            }
+            return GIB_1;
+        }
+        let l2_table = match self.descend_splitting(l3_table, virt.p3_index(), virt, MIB_2, alloc) {
+            Some(table) => table,
+            None => return skip_span(virt, GIB_1, remaining),
+        };
+
+        if l2_table[virt.p2_index()].flags().contains(PageTableFlags::HUGE_PAGE) && fully_covers(virt, MIB_2, remaining) {
+            let entry = &mut l2_table[virt.p2_index()];
+            let freed = PhysAddr::new(entry.addr().as_u64() & !HUGE_PAT_BIT);
+            entry.set_unused();
+            self.maybe_flush(virt);
+            on_freed(unsafe { Owned4KibFrame::from_mapped(freed) });
+            if free_empty_intermediates && Self::free_if_empty(l2_table, &mut l3_table[virt.p3_index()], on_freed) {
+                Self::free_if_empty(l3_table, &mut l4_table[virt.p4_index()], on_freed);
+            }
+            return MIB_2;
+        }
+        let l1_table = match self.descend_splitting(l2_table, virt.p2_index(), virt, PAGE_SIZE, alloc) {
+            Some(table) => table,
+            None => return skip_span(virt, MIB_2, remaining),
+        };
+
+        let l1_entry = &mut l1_table[virt.p1_index()];
+        if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
+            return PAGE_SIZE;
+        }
+        let freed = l1_entry.addr();
+        l1_entry.set_unused();
+        self.maybe_flush(virt);
+        on_freed(unsafe { Owned4KibFrame::from_mapped(freed) });
+
+        if free_empty_intermediates
+            && Self::free_if_empty(l1_table, &mut l2_table[virt.p2_index()], on_freed)
+            && Self::free_if_empty(l2_table, &mut l3_table[virt.p3_index()], on_freed)
+        {
+            Self::free_if_empty(l3_table, &mut l4_table[virt.p4_index()], on_freed);
+        }
+        PAGE_SIZE
+    }
+
+    /// Change the flags of every mapped page in `virt` to `flags`, leaving
+    /// pages that aren't currently mapped untouched.
+    ///
+    /// A `HUGE_PAGE` block that `virt` only partially covers is first split
+    /// into a full child table at the next-smaller granularity (see
+    /// [`Self::split_block`]) so the unaffected part of the block keeps its
+    /// original flags instead of picking up `flags` too; a block `virt`
+    /// covers in full is reflagged directly instead, without ever allocating
+    /// a child table for it (see [`fully_covers`]). If this table
+    /// [`Self::is_active`], every split and every updated entry is flushed
+    /// from the TLB as it happens; an inactive table skips this entirely.
+    ///
+    /// # Panics
+    /// Panics if `virt` is not 4 KiB aligned at both ends, or if `alloc`
+    /// cannot supply a frame to split a block that `virt` only partially
+    /// covers.
+    pub fn protect_range(&mut self, virt: Range<VirtAddr>, flags: PageTableFlags, alloc: &mut impl FrameAllocator) {
+        self.config.assert_offset_mode("protect_range");
+        assert_eq!(virt.start.as_u64() % PAGE_SIZE, 0, "virt.start must be 4 KiB aligned");
+        assert_eq!(virt.end.as_u64() % PAGE_SIZE, 0, "virt.end must be 4 KiB aligned");
+
+        let mut virt_cursor = virt.start;
+        while virt_cursor < virt.end {
+            let remaining = virt.end.as_u64() - virt_cursor.as_u64();
+            virt_cursor += self.protect_block(virt_cursor, remaining, flags, alloc);
+        }
+    }
+
+    /// Reflag whatever is mapped at `virt` to `flags`, splitting a
+    /// `HUGE_PAGE` block first only if `remaining` doesn't cover it in full.
+    /// Returns how many bytes starting at `virt` were accounted for, so the
+    /// caller can advance its cursor by that much.
+    fn protect_block(&mut self, virt: VirtAddr, remaining: u64, flags: PageTableFlags, alloc: &mut impl FrameAllocator) -> u64 {
+        let l4_table = unsafe { self.page_table().as_mut() };
+        let l3_table = match Self::child_table_mut(l4_table, virt.p4_index(), &self.config) {
+            Some(table) => table,
+            None => return skip_span(virt, GIB_1, remaining),
+        };
+
+        let l3_entry = &mut l3_table[virt.p3_index()];
+        if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) && fully_covers(virt, GIB_1, remaining) {
+            let addr = l3_entry.addr();
+            l3_entry.set_addr(addr, flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+            self.maybe_flush(virt);
+            return GIB_1;
+        }
+        let l2_table = match self.descend_splitting(l3_table, virt.p3_index(), virt, MIB_2, alloc) {
+            Some(table) => table,
+            None => return skip_span(virt, GIB_1, remaining),
+        };
+
+        let l2_entry = &mut l2_table[virt.p2_index()];
+        if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) && fully_covers(virt, MIB_2, remaining) {
+            let addr = l2_entry.addr();
+            l2_entry.set_addr(addr, flags | PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE);
+            self.maybe_flush(virt);
+            return MIB_2;
+        }
+        let l1_table = match self.descend_splitting(l2_table, virt.p2_index(), virt, PAGE_SIZE, alloc) {
+            Some(table) => table,
+            None => return skip_span(virt, MIB_2, remaining),
+        };
+
+        let l1_entry = &mut l1_table[virt.p1_index()];
+        if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
+            return PAGE_SIZE;
+        }
+        let addr = l1_entry.addr();
+        l1_entry.set_addr(addr, flags | PageTableFlags::PRESENT);
+        self.maybe_flush(virt);
+        PAGE_SIZE
+    }
+
+    /// Follow a `PRESENT` entry down to its child table, or `None` if it
+    /// isn't mapped at all.
+    fn child_table_mut<'a>(
+        table: &PageTable,
+        index: PageTableIndex,
+        config: &PagingConfig,
+    ) -> Option<&'a mut PageTable> {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        Some(unsafe { &mut *entry.addr().to_virt(config).as_mut_ptr::<PageTable>() })
+    }
+
+    /// Like [`Self::child_table_mut`], but if `table[index]` is a
+    /// `HUGE_PAGE` block, it is first split into a full child table at
+    /// `sub_page_size` granularity via [`Self::split_block`].
+    fn descend_splitting<'a>(
+        &self,
+        table: &'a mut PageTable,
+        index: PageTableIndex,
+        virt: VirtAddr,
+        sub_page_size: u64,
+        alloc: &mut impl FrameAllocator,
+    ) -> Option<&'a mut PageTable> {
+        let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            self.split_block(entry, sub_page_size, alloc);
+            self.maybe_flush(virt.align_down(sub_page_size * 512));
+        }
+        Some(unsafe { &mut *entry.addr().to_virt(&self.config).as_mut_ptr::<PageTable>() })
+    }
+
+    /// Split a `HUGE_PAGE` block entry into a freshly allocated child table
+    /// at `sub_page_size` granularity, reproducing the block's mapping
+    /// (same flags, physical base incremented by `sub_page_size` per index)
+    /// across all 512 entries, then repoint `entry` at the new table with
+    /// `HUGE_PAGE` cleared.
+    ///
+    /// Splitting a 1 GiB block into 2 MiB ones reuses the parent's flags
+    /// as-is, since a 2 MiB entry keeps its PAT selector at the same bit
+    /// (12) a 1 GiB one does. Splitting a 2 MiB block into 4 KiB leaves
+    /// needs the PAT selector relocated to bit 7 instead; see
+    /// [`relocate_huge_pat_bit`].
+    ///
+    /// # Panics
+    /// Panics if `alloc` cannot supply a frame for the child table.
+    fn split_block(&self, entry: &mut PageTableEntry, sub_page_size: u64, alloc: &mut impl FrameAllocator) {
+        let flags = entry.flags();
+        let block_base = entry.addr();
+        let (block_base, child_flags) = if sub_page_size == PAGE_SIZE {
+            relocate_huge_pat_bit(block_base, flags)
+        } else {
+            (block_base, flags)
+        };
+
+        let mut child_frame = alloc
+            .allocate_frame()
+            .expect("out of physical memory to split a huge page block");
+        let child_table =
+            unsafe { &mut *child_frame.start_address().to_virt(&self.config).as_mut_ptr::<PageTable>() };
+        for (i, child_entry) in child_table.iter_mut().enumerate() {
+            child_entry.set_addr(block_base + (i as u64) * sub_page_size, child_flags);
+        }
+
+        entry.set_addr(
+            child_frame.start_address(),
+            (flags & !PageTableFlags::HUGE_PAGE) | PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+        // Ownership of the frame now lives in the page table entry, not in a Rust value.
+        core::mem::forget(child_frame);
+    }
+
+    /// If every entry in `table` is unused, clear `parent_entry` and hand the
+    /// freed frame to `on_freed`. Returns whether `table` was freed.
+    fn free_if_empty(
+        table: &PageTable,
+        parent_entry: &mut PageTableEntry,
+        on_freed: &mut impl FnMut(Owned4KibFrame),
+    ) -> bool {
+        if !table.iter().all(PageTableEntry::is_unused) {
+            return false;
+        }
+        let freed = parent_entry.addr();
+        parent_entry.set_unused();
+        on_freed(unsafe { Owned4KibFrame::from_mapped(freed) });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pat_msr_index_combines_write_through_no_cache_and_pat_bit() {
+        assert_eq!(pat_msr_index(PageTableFlags::empty(), false), 0b000);
+        assert_eq!(pat_msr_index(PageTableFlags::WRITE_THROUGH, false), 0b001);
+        assert_eq!(pat_msr_index(PageTableFlags::NO_CACHE, false), 0b010);
+        assert_eq!(pat_msr_index(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE, false), 0b011);
+        assert_eq!(pat_msr_index(PageTableFlags::empty(), true), 0b100);
+        assert_eq!(
+            pat_msr_index(PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE, true),
+            0b111
+        );
+    }
+
+    #[test]
+    fn coalesced_block_size_picks_largest_size_both_sides_agree_on() {
+        let aligned = |n: u64| (VirtAddr::new(n), PhysAddr::new(n));
+
+        let (virt, phys) = aligned(GIB_1);
+        assert_eq!(ManagedL4PageTable::coalesced_block_size(virt, phys, GIB_1), GIB_1);
+        assert_eq!(ManagedL4PageTable::coalesced_block_size(virt, phys, GIB_1 - 1), MIB_2);
+
+        let (virt, phys) = aligned(MIB_2);
+        assert_eq!(ManagedL4PageTable::coalesced_block_size(virt, phys, GIB_1), MIB_2);
+        assert_eq!(ManagedL4PageTable::coalesced_block_size(virt, phys, MIB_2 - 1), PAGE_SIZE);
+
+        // Misaligned virt forces 4 KiB even when phys and the remaining size
+        // would both otherwise allow a 2 MiB block.
+        let virt = VirtAddr::new(MIB_2 + PAGE_SIZE);
+        let phys = PhysAddr::new(MIB_2);
+        assert_eq!(ManagedL4PageTable::coalesced_block_size(virt, phys, GIB_1), PAGE_SIZE);
+    }
+
+    #[test]
+    fn relocate_huge_pat_bit_moves_bit_12_to_bit_7_and_masks_the_base() {
+        let base = PhysAddr::new(0x1000_0000);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE | PageTableFlags::WRITE_THROUGH;
+
+        // PAT bit clear: HUGE_PAGE (the leaf's PAT selector) is cleared, base untouched.
+        let (relocated_base, relocated_flags) = relocate_huge_pat_bit(base, flags);
+        assert_eq!(relocated_base, base);
+        assert!(!relocated_flags.contains(PageTableFlags::HUGE_PAGE));
+        assert!(relocated_flags.contains(PageTableFlags::WRITE_THROUGH));
+
+        // PAT bit set (bit 12 of the address): relocated to bit 7, masked out of the base.
+        let huge_addr_with_pat = PhysAddr::new(base.as_u64() | HUGE_PAT_BIT);
+        let (relocated_base, relocated_flags) = relocate_huge_pat_bit(huge_addr_with_pat, flags);
+        assert_eq!(relocated_base, base);
+        assert!(relocated_flags.contains(PageTableFlags::HUGE_PAGE));
+        assert!(relocated_flags.contains(PageTableFlags::WRITE_THROUGH));
+    }
+
+    #[test]
+    fn fully_covers_requires_alignment_and_enough_remaining() {
+        assert!(fully_covers(VirtAddr::new(MIB_2), MIB_2, MIB_2));
+        assert!(fully_covers(VirtAddr::new(MIB_2), MIB_2, MIB_2 + PAGE_SIZE));
+        // Not enough of the request left to cover the whole block.
+        assert!(!fully_covers(VirtAddr::new(MIB_2), MIB_2, MIB_2 - PAGE_SIZE));
+        // `virt` doesn't sit on a block boundary.
+        assert!(!fully_covers(VirtAddr::new(MIB_2 + PAGE_SIZE), MIB_2, MIB_2));
+    }
+
+    #[test]
+    fn skip_span_jumps_to_the_next_block_boundary_capped_by_remaining() {
+        assert_eq!(skip_span(VirtAddr::new(0), MIB_2, GIB_1), MIB_2);
+        assert_eq!(skip_span(VirtAddr::new(PAGE_SIZE), MIB_2, GIB_1), MIB_2 - PAGE_SIZE);
+        // Capped by `remaining` when the request ends before the next boundary.
+        assert_eq!(skip_span(VirtAddr::new(PAGE_SIZE), MIB_2, PAGE_SIZE), PAGE_SIZE);
+    }
 }
@@ -0,0 +1,161 @@
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PageTableIndex};
+
+/// How this crate's managed page tables reach a page table frame's contents
+/// from its physical address.
+#[derive(Debug, Clone, Copy)]
+pub enum PagingAccessMode {
+    /// All of physical memory is mapped at a fixed virtual offset, so a
+    /// frame's virtual address is just `phys + offset`.
+    Offset(VirtAddr),
+    /// One L4 entry, `recursive_index`, points back at the L4 frame itself,
+    /// so any page table frame can be reached through a well-known recursive
+    /// virtual address instead of mapping all of physical RAM. The caller is
+    /// responsible for pointing that L4 entry at the L4 frame before relying
+    /// on this mode. See [`PagingConfig::child_table_virt`].
+    ///
+    /// `recursive_index` must be in the upper half of the L4 table (>= 256);
+    /// see [`PagingConfig::recursive`].
+    Recursive { recursive_index: PageTableIndex },
+}
+
+/// Describes how this crate's managed page tables are themselves accessed
+/// while being walked.
+#[derive(Debug, Clone, Copy)]
+pub struct PagingConfig {
+    access_mode: PagingAccessMode,
+}
+
+impl PagingConfig {
+    /// All of physical memory is mapped at `offset`.
+    pub const fn offset(offset: VirtAddr) -> Self {
+        Self {
+            access_mode: PagingAccessMode::Offset(offset),
+        }
+    }
+
+    /// Page tables are reached recursively through L4 entry `recursive_index`.
+    ///
+    /// # Panics
+    /// Panics unless `recursive_index` is in the upper half of the L4 table
+    /// (>= 256). [`Self::recursive_virt`] always places `recursive_index` in
+    /// its top slot (see [`Self::child_table_virt`]) and hard-codes the
+    /// resulting address' sign extension to all-ones, which is only
+    /// canonical when that slot's index has bit 8 set; a lower-half
+    /// `recursive_index` would make every recursively-resolved address
+    /// non-canonical.
+    pub fn recursive(recursive_index: PageTableIndex) -> Self {
+        assert!(
+            u16::from(recursive_index) >= 256,
+            "PagingConfig::recursive requires an upper-half L4 index (>= 256) for a canonical recursive address",
+        );
+        Self {
+            access_mode: PagingAccessMode::Recursive { recursive_index },
+        }
+    }
+
+    /// Panic if this isn't [`PagingAccessMode::Offset`]; `context` names the
+    /// caller for the panic message.
+    ///
+    /// Reaching an L1/L2/L3 frame in [`PagingAccessMode::Recursive`] mode
+    /// needs the virtual address path that was walked to find it, which only
+    /// [`Self::child_table_virt`] has access to; read-only walks like
+    /// [`crate::ManagedL4PageTable::translate`] go through that path, but the
+    /// allocating mapping helpers (`map_range`, `unmap_range`,
+    /// `create_guard_page`, …) still resolve every intermediate table
+    /// through the fixed-offset scheme. Calling them in
+    /// [`PagingAccessMode::Recursive`] mode would silently read and write
+    /// the wrong frames instead of failing, so they call this first.
+    pub(crate) fn assert_offset_mode(&self, context: &str) {
+        assert!(
+            matches!(self.access_mode, PagingAccessMode::Offset(_)),
+            "{context} does not support PagingAccessMode::Recursive yet",
+        );
+    }
+
+    /// Resolve the virtual address of the child table reached while walking
+    /// toward `addr`, `levels_from_l4` steps below L4 (1 for L3, 2 for L2, 3
+    /// for L1), given that the entry pointing at it holds `entry_phys`.
+    ///
+    /// In recursive mode, a table at level L is reached by repeating
+    /// `recursive_index` for the upper levels and substituting `addr`'s own
+    /// path indexes for the lower ones, so `entry_phys` isn't actually needed
+    /// there; it's only used in offset mode.
+    pub(crate) fn child_table_virt(&self, addr: VirtAddr, levels_from_l4: u8, entry_phys: PhysAddr) -> VirtAddr {
+        match self.access_mode {
+            PagingAccessMode::Offset(offset) => offset + entry_phys.as_u64(),
+            PagingAccessMode::Recursive { recursive_index } => {
+                let real_path = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+                let mut indices = [recursive_index; 4];
+                for i in 0..levels_from_l4 as usize {
+                    indices[4 - levels_from_l4 as usize + i] = real_path[i];
+                }
+                Self::recursive_virt(indices)
+            }
+        }
+    }
+
+    /// Build the canonical, sign-extended virtual address for the recursive
+    /// path `indices` (in L4, L3, L2, L1 order).
+    fn recursive_virt(indices: [PageTableIndex; 4]) -> VirtAddr {
+        let sign_extension = 0xFFFFu64 << 48;
+        let raw = sign_extension
+            | (u64::from(u16::from(indices[0])) << 39)
+            | (u64::from(u16::from(indices[1])) << 30)
+            | (u64::from(u16::from(indices[2])) << 21)
+            | (u64::from(u16::from(indices[3])) << 12);
+        VirtAddr::new(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idx(i: u16) -> PageTableIndex {
+        PageTableIndex::new(i)
+    }
+
+    #[test]
+    fn recursive_virt_all_recursive_is_canonical_and_sign_extended() {
+        let config = PagingConfig::recursive(idx(256));
+        let virt = config.child_table_virt(VirtAddr::new(0), 0, PhysAddr::new(0));
+        assert_eq!(virt.as_u64(), 0xFFFF_8040_2010_0000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn recursive_rejects_a_lower_half_index() {
+        PagingConfig::recursive(idx(255));
+    }
+
+    #[test]
+    fn child_table_virt_offset_mode_ignores_addr_and_levels() {
+        let config = PagingConfig::offset(VirtAddr::new(0xFFFF_8000_0000_0000));
+        let phys = PhysAddr::new(0x1234_000);
+        let virt = config.child_table_virt(VirtAddr::new(0x1000), 1, phys);
+        assert_eq!(virt, VirtAddr::new(0xFFFF_8000_0000_0000) + phys.as_u64());
+    }
+
+    #[test]
+    fn child_table_virt_recursive_substitutes_real_path_for_lower_levels() {
+        let recursive_index = idx(0o400);
+        let config = PagingConfig::recursive(recursive_index);
+        let addr = VirtAddr::new(0x1234_5678_9000);
+
+        // One level below L4 (an L3 table): only the bottom index slot is the
+        // real p4_index, the rest stay the recursive index.
+        let l3_virt = config.child_table_virt(addr, 1, PhysAddr::new(0));
+        let expected_l3 = PagingConfig::recursive_virt([recursive_index, recursive_index, recursive_index, addr.p4_index()]);
+        assert_eq!(l3_virt, expected_l3);
+
+        // Two levels below L4 (an L2 table): the bottom two slots are real.
+        let l2_virt = config.child_table_virt(addr, 2, PhysAddr::new(0));
+        let expected_l2 = PagingConfig::recursive_virt([recursive_index, recursive_index, addr.p4_index(), addr.p3_index()]);
+        assert_eq!(l2_virt, expected_l2);
+
+        // Three levels below L4 (an L1 table): everything but the top slot is real.
+        let l1_virt = config.child_table_virt(addr, 3, PhysAddr::new(0));
+        let expected_l1 = PagingConfig::recursive_virt([recursive_index, addr.p4_index(), addr.p3_index(), addr.p2_index()]);
+        assert_eq!(l1_virt, expected_l1);
+    }
+}